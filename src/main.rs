@@ -2,6 +2,14 @@ use std::ffi::c_int;
 
 use cudarc::driver::result as cuda;
 use cudarc::driver::sys::{self, CUdevice_attribute::*};
+use serde::Serialize;
+
+// cudarc only wraps the Driver API, so the one Runtime-API call this tool needs is declared
+// and linked against `cudart` directly rather than going through `sys::`.
+#[link(name = "cudart")]
+extern "C" {
+    fn cudaRuntimeGetVersion(version: *mut c_int) -> c_int;
+}
 
 /// Maps SM version (major, minor) to CUDA cores per SM.
 fn sm_to_cores(major: i32, minor: i32) -> Option<i32> {
@@ -43,12 +51,132 @@ fn driver_version() -> Result<c_int, cudarc::driver::DriverError> {
     Ok(version)
 }
 
+/// Queries the CUDA Runtime version (the toolkit a binary was built against), as opposed to
+/// `driver_version` which reports the installed driver. A driver older than the runtime is a
+/// common misconfiguration users diagnose by comparing the two.
+fn runtime_version() -> Result<c_int, cudarc::driver::DriverError> {
+    let mut version: c_int = 0;
+    // 0 is cudaSuccess; anything else means the toolkit call itself failed.
+    let result = unsafe { cudaRuntimeGetVersion(&mut version) };
+    if result != 0 {
+        return Ok(0);
+    }
+    Ok(version)
+}
+
 fn can_access_peer(dev: sys::CUdevice, peer: sys::CUdevice) -> bool {
     let mut can_access: c_int = 0;
     let result = unsafe { sys::cuDeviceCanAccessPeer(&mut can_access, dev, peer) };
     result == sys::CUresult::CUDA_SUCCESS && can_access != 0
 }
 
+/// Size (in bytes) of the buffer copied from one device to the other when benchmarking P2P bandwidth.
+const P2P_BUFFER_SIZE: usize = 128 * 1024 * 1024; // 128 MB
+
+/// Number of timed copies averaged together to compute the reported GB/s figure.
+const P2P_ITERATIONS: u32 = 10;
+
+/// Creates and retains a context for `dev`, for reuse across every pair the device appears in.
+fn create_context(dev: sys::CUdevice) -> Option<sys::CUcontext> {
+    let mut ctx: sys::CUcontext = std::ptr::null_mut();
+    unsafe {
+        if sys::cuCtxCreate_v2(&mut ctx, 0, dev) != sys::CUresult::CUDA_SUCCESS {
+            return None;
+        }
+    }
+    Some(ctx)
+}
+
+/// Benchmarks the achieved copy bandwidth (in GB/s) between `ctx` and `peer_ctx`, reusing the
+/// contexts the caller created. Returns `None` if any step fails.
+fn measure_bandwidth_gbps(
+    ctx: sys::CUcontext,
+    peer_ctx: sys::CUcontext,
+    same_device: bool,
+) -> Option<f64> {
+    unsafe {
+        if !same_device {
+            sys::cuCtxSetCurrent(ctx);
+            if sys::cuCtxEnablePeerAccess(peer_ctx, 0) != sys::CUresult::CUDA_SUCCESS {
+                return None;
+            }
+        }
+
+        sys::cuCtxSetCurrent(ctx);
+        let mut src: sys::CUdeviceptr = 0;
+        if sys::cuMemAlloc_v2(&mut src, P2P_BUFFER_SIZE) != sys::CUresult::CUDA_SUCCESS {
+            if !same_device {
+                sys::cuCtxDisablePeerAccess(peer_ctx);
+            }
+            return None;
+        }
+
+        let dst_ctx = if same_device { ctx } else { peer_ctx };
+        sys::cuCtxSetCurrent(dst_ctx);
+        let mut dst: sys::CUdeviceptr = 0;
+        if sys::cuMemAlloc_v2(&mut dst, P2P_BUFFER_SIZE) != sys::CUresult::CUDA_SUCCESS {
+            sys::cuCtxSetCurrent(ctx);
+            sys::cuMemFree_v2(src);
+            if !same_device {
+                sys::cuCtxDisablePeerAccess(peer_ctx);
+            }
+            return None;
+        }
+
+        sys::cuCtxSetCurrent(ctx);
+
+        let mut start: sys::CUevent = std::ptr::null_mut();
+        let mut stop: sys::CUevent = std::ptr::null_mut();
+        sys::cuEventCreate(&mut start, sys::CUevent_flags_enum::CU_EVENT_DEFAULT as c_int);
+        sys::cuEventCreate(&mut stop, sys::CUevent_flags_enum::CU_EVENT_DEFAULT as c_int);
+
+        let do_copy = || -> sys::CUresult {
+            if same_device {
+                sys::cuMemcpyDtoD_v2(dst, src, P2P_BUFFER_SIZE)
+            } else {
+                sys::cuMemcpyPeer(dst, peer_ctx, src, ctx, P2P_BUFFER_SIZE)
+            }
+        };
+
+        // Warm up once so the first (often slower) transfer doesn't skew the timing.
+        do_copy();
+
+        sys::cuEventRecord(start, std::ptr::null_mut());
+        let mut ok = true;
+        for _ in 0..P2P_ITERATIONS {
+            if do_copy() != sys::CUresult::CUDA_SUCCESS {
+                ok = false;
+                break;
+            }
+        }
+        sys::cuEventRecord(stop, std::ptr::null_mut());
+        sys::cuEventSynchronize(stop);
+
+        let mut elapsed_ms: f32 = 0.0;
+        let timed = ok && sys::cuEventElapsedTime(&mut elapsed_ms, start, stop) == sys::CUresult::CUDA_SUCCESS;
+
+        sys::cuEventDestroy_v2(start);
+        sys::cuEventDestroy_v2(stop);
+
+        sys::cuCtxSetCurrent(dst_ctx);
+        sys::cuMemFree_v2(dst);
+        sys::cuCtxSetCurrent(ctx);
+        sys::cuMemFree_v2(src);
+
+        if !same_device {
+            sys::cuCtxDisablePeerAccess(peer_ctx);
+        }
+
+        if !timed || elapsed_ms <= 0.0 {
+            return None;
+        }
+
+        let bytes_transferred = P2P_BUFFER_SIZE as f64 * P2P_ITERATIONS as f64;
+        let seconds = elapsed_ms as f64 / 1000.0;
+        Some(bytes_transferred / seconds / 1e9)
+    }
+}
+
 fn compute_mode_str(mode: i32) -> &'static str {
     match mode {
         0 => "Default (multiple host threads can use ::cudaSetDevice() with device simultaneously)",
@@ -63,7 +191,221 @@ fn compute_mode_str(mode: i32) -> &'static str {
     }
 }
 
+/// Output mode selected with `--format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    /// The original human-readable, fixed-width columns (default).
+    Text,
+    /// A single JSON document, for piping into scripts or inventory tools.
+    Json,
+}
+
+impl OutputFormat {
+    fn is_text(self) -> bool {
+        matches!(self, OutputFormat::Text)
+    }
+}
+
+/// Parses `--format <text|json>` out of the process arguments, defaulting to `Text`.
+fn parse_format(args: &[String]) -> OutputFormat {
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--format" {
+            return match args.get(i + 1).map(String::as_str) {
+                Some("json") => OutputFormat::Json,
+                Some("text") | None => OutputFormat::Text,
+                Some(other) => {
+                    eprintln!("Unknown --format value '{}', falling back to 'text'", other);
+                    OutputFormat::Text
+                }
+            };
+        }
+    }
+    OutputFormat::Text
+}
+
+/// Device-selection predicates, modeled on the `configureGpu` helper in the CUDA samples:
+/// a device is reported only if it satisfies every constraint the user passed on the CLI.
+#[derive(Debug, Clone, Copy, Default)]
+struct DeviceFilters {
+    /// Minimum `major * 10 + minor` compute capability, from `--min-cc`.
+    min_cc: Option<i32>,
+    /// Minimum global memory in MB, from `--min-memory`.
+    min_memory_mb: Option<u64>,
+    /// Restrict to a single device index, from `--device`.
+    only_device: Option<c_int>,
+}
+
+impl DeviceFilters {
+    fn allows(&self, index: c_int, major: i32, minor: i32, total_mem_mb: f64) -> bool {
+        if let Some(only) = self.only_device {
+            if index != only {
+                return false;
+            }
+        }
+        if let Some(min_cc) = self.min_cc {
+            if major * 10 + minor < min_cc {
+                return false;
+            }
+        }
+        if let Some(min_mb) = self.min_memory_mb {
+            if (total_mem_mb as u64) < min_mb {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn is_active(&self) -> bool {
+        self.min_cc.is_some() || self.min_memory_mb.is_some() || self.only_device.is_some()
+    }
+}
+
+/// Parses `major.minor` (e.g. `"7.0"`) into `major * 10 + minor` for a `--min-cc` comparison.
+fn parse_compute_capability(value: &str) -> Option<i32> {
+    let mut parts = value.splitn(2, '.');
+    let major: i32 = parts.next()?.parse().ok()?;
+    let minor: i32 = parts.next().unwrap_or("0").parse().ok()?;
+    Some(major * 10 + minor)
+}
+
+/// Parses `--min-cc`, `--min-memory` and `--device` out of the process arguments.
+///
+/// These flags exist so launcher/CI scripts can assert a box has an adequate GPU before
+/// running on it, so a missing or malformed value is treated as a usage error (exit 2)
+/// rather than silently disabling the filter the caller asked for.
+fn parse_filters(args: &[String]) -> DeviceFilters {
+    let mut filters = DeviceFilters::default();
+    for (i, arg) in args.iter().enumerate() {
+        match arg.as_str() {
+            "--min-cc" => {
+                let value = args.get(i + 1).unwrap_or_else(|| {
+                    eprintln!("--min-cc requires a value, e.g. --min-cc 7.0");
+                    std::process::exit(2);
+                });
+                filters.min_cc = Some(parse_compute_capability(value).unwrap_or_else(|| {
+                    eprintln!("Invalid --min-cc value '{}', expected e.g. '7.0'", value);
+                    std::process::exit(2);
+                }));
+            }
+            "--min-memory" => {
+                let value = args.get(i + 1).unwrap_or_else(|| {
+                    eprintln!("--min-memory requires a value in MB, e.g. --min-memory 8192");
+                    std::process::exit(2);
+                });
+                filters.min_memory_mb = Some(value.parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid --min-memory value '{}', expected an integer", value);
+                    std::process::exit(2);
+                }));
+            }
+            "--device" => {
+                let value = args.get(i + 1).unwrap_or_else(|| {
+                    eprintln!("--device requires a value, e.g. --device 0");
+                    std::process::exit(2);
+                });
+                filters.only_device = Some(value.parse().unwrap_or_else(|_| {
+                    eprintln!("Invalid --device value '{}', expected an integer", value);
+                    std::process::exit(2);
+                }));
+            }
+            _ => {}
+        }
+    }
+    filters
+}
+
+#[derive(Serialize)]
+struct ComputeCapability {
+    major: i32,
+    minor: i32,
+}
+
+#[derive(Serialize)]
+struct Clocks {
+    gpu_max_clock_mhz: f64,
+    memory_clock_mhz: f64,
+}
+
+#[derive(Serialize)]
+struct CacheSizes {
+    l2_cache_bytes: i32,
+    shared_mem_per_block_bytes: i32,
+    shared_mem_per_multiprocessor_bytes: i32,
+    total_constant_memory_bytes: i32,
+}
+
+#[derive(Serialize)]
+struct FeatureFlags {
+    concurrent_copy_and_kernel_execution: bool,
+    run_time_limit_on_kernels: bool,
+    integrated: bool,
+    can_map_host_memory: bool,
+    ecc_enabled: bool,
+    unified_addressing: bool,
+    managed_memory: bool,
+    compute_preemption: bool,
+    cooperative_launch: bool,
+    cooperative_multi_device_launch: bool,
+}
+
+#[derive(Serialize)]
+struct PciLocation {
+    domain: i32,
+    bus: i32,
+    device: i32,
+}
+
+#[derive(Serialize)]
+struct DeviceReport {
+    index: c_int,
+    name: String,
+    total_memory_bytes: u64,
+    compute_capability: ComputeCapability,
+    multiprocessor_count: i32,
+    cuda_cores: Option<i32>,
+    peak_gflops_fp32: Option<f64>,
+    clocks: Clocks,
+    cache_sizes: CacheSizes,
+    feature_flags: FeatureFlags,
+    pci: PciLocation,
+    compute_mode: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+    driver_version: String,
+    runtime_version: String,
+    device_count: usize,
+    devices: Vec<DeviceReport>,
+    p2p_bandwidth_matrix_gbps: Vec<Vec<Option<f64>>>,
+    fastest_device_index: Option<c_int>,
+}
+
+/// A single-field JSON error body, for failures reported after `--format json` has already
+/// committed the output to being machine-parseable.
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    error: &'a str,
+}
+
+/// Picks the device with the highest estimated peak GFLOPS, breaking ties by larger global memory.
+fn fastest_device(reports: &[DeviceReport]) -> Option<&DeviceReport> {
+    reports
+        .iter()
+        .filter_map(|r| r.peak_gflops_fp32.map(|gflops| (r, gflops)))
+        .max_by(|(a, a_gflops), (b, b_gflops)| {
+            a_gflops
+                .partial_cmp(b_gflops)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.total_memory_bytes.cmp(&b.total_memory_bytes))
+        })
+        .map(|(r, _)| r)
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let format = parse_format(&args);
+    let filters = parse_filters(&args);
+
     // Initialize the CUDA driver API
     if let Err(e) = cuda::init() {
         eprintln!("Failed to initialize CUDA driver: {:?}", e);
@@ -71,6 +413,7 @@ fn main() {
     }
 
     let driver_ver = driver_version().unwrap_or(0);
+    let runtime_ver = runtime_version().unwrap_or(0);
 
     let dev_count = match cuda::device::get_count() {
         Ok(n) => n,
@@ -81,13 +424,45 @@ fn main() {
     };
 
     if dev_count == 0 {
-        println!("There are no available device(s) that support CUDA");
+        if format.is_text() {
+            println!("There are no available device(s) that support CUDA");
+        } else {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&Report {
+                    driver_version: format!("{}.{}", driver_ver / 1000, (driver_ver % 1000) / 10),
+                    runtime_version: format!(
+                        "{}.{}",
+                        runtime_ver / 1000,
+                        (runtime_ver % 1000) / 10
+                    ),
+                    device_count: 0,
+                    devices: Vec::new(),
+                    p2p_bandwidth_matrix_gbps: Vec::new(),
+                    fastest_device_index: None,
+                })
+                .unwrap()
+            );
+        }
         std::process::exit(0);
     }
 
-    println!("Detected {} CUDA Capable device(s)\n", dev_count);
+    if format.is_text() {
+        if filters.is_active() {
+            println!(
+                "Detected {} CUDA Capable device(s), filtering by --min-cc/--min-memory/--device\n",
+                dev_count
+            );
+        } else {
+            println!("Detected {} CUDA Capable device(s)\n", dev_count);
+        }
+    }
 
-    let mut devices = Vec::new();
+    // Keeps the real device index alongside each `CUdevice` handle so that anything printed
+    // after filtering (the P2P matrix labels, in particular) still refers to the same device
+    // numbering as the per-device sections above it, rather than its position in this vector.
+    let mut devices: Vec<(c_int, sys::CUdevice)> = Vec::new();
+    let mut reports = Vec::new();
 
     for i in 0..dev_count {
         let dev = match cuda::device::get(i) {
@@ -97,68 +472,101 @@ fn main() {
                 continue;
             }
         };
-        devices.push(dev);
 
         let name = cuda::device::get_name(dev).unwrap_or_else(|_| "Unknown".to_string());
         let total_mem = unsafe { cuda::device::total_mem(dev).unwrap_or(0) };
+        let total_mem_mb = total_mem as f64 / (1024.0 * 1024.0);
 
         let major = attr(dev, CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MAJOR);
         let minor = attr(dev, CU_DEVICE_ATTRIBUTE_COMPUTE_CAPABILITY_MINOR);
 
-        println!("Device {}: \"{}\"", i, name);
-        println!(
-            "  CUDA Driver Version:                           {}.{}",
-            driver_ver / 1000,
-            (driver_ver % 1000) / 10
-        );
-        println!(
-            "  CUDA Capability Major/Minor version number:    {}.{}",
-            major, minor
-        );
+        if !filters.allows(i, major, minor, total_mem_mb) {
+            continue;
+        }
+        devices.push((i, dev));
 
-        let total_mem_mb = total_mem as f64 / (1024.0 * 1024.0);
-        println!(
-            "  Total amount of global memory:                 {:.0} MBytes ({} bytes)",
-            total_mem_mb, total_mem
-        );
+        if format.is_text() {
+            println!("Device {}: \"{}\"", i, name);
+            println!(
+                "  CUDA Driver Version / Runtime Version          {}.{} / {}.{}",
+                driver_ver / 1000,
+                (driver_ver % 1000) / 10,
+                runtime_ver / 1000,
+                (runtime_ver % 1000) / 10
+            );
+            println!(
+                "  CUDA Capability Major/Minor version number:    {}.{}",
+                major, minor
+            );
+        }
+
+        if format.is_text() {
+            println!(
+                "  Total amount of global memory:                 {:.0} MBytes ({} bytes)",
+                total_mem_mb, total_mem
+            );
+        }
 
         let mp_count = attr(dev, CU_DEVICE_ATTRIBUTE_MULTIPROCESSOR_COUNT);
         let cores_per_mp = sm_to_cores(major, minor);
 
-        match cores_per_mp {
-            Some(cores) => println!(
-                "  ({:03}) Multiprocessors, ({:03}) CUDA Cores/MP:    {} CUDA Cores",
-                mp_count,
-                cores,
-                mp_count * cores
-            ),
-            None => println!(
-                "  ({:03}) Multiprocessors (unknown CUDA Cores/MP for SM {}.{})",
-                mp_count, major, minor
-            ),
+        if format.is_text() {
+            match cores_per_mp {
+                Some(cores) => println!(
+                    "  ({:03}) Multiprocessors, ({:03}) CUDA Cores/MP:    {} CUDA Cores",
+                    mp_count,
+                    cores,
+                    mp_count * cores
+                ),
+                None => println!(
+                    "  ({:03}) Multiprocessors (unknown CUDA Cores/MP for SM {}.{})",
+                    mp_count, major, minor
+                ),
+            }
         }
 
         let clock_rate = attr(dev, CU_DEVICE_ATTRIBUTE_CLOCK_RATE); // in kHz
-        println!(
-            "  GPU Max Clock rate:                            {:.0} MHz ({:.2} GHz)",
-            clock_rate as f64 / 1000.0,
-            clock_rate as f64 / 1_000_000.0
-        );
+        if format.is_text() {
+            println!(
+                "  GPU Max Clock rate:                            {:.0} MHz ({:.2} GHz)",
+                clock_rate as f64 / 1000.0,
+                clock_rate as f64 / 1_000_000.0
+            );
+        }
+
+        // Rough theoretical peak, the same `cores * clock * 2` (FMA) estimate used by the
+        // `gpuGetMaxGflopsDeviceId` helper in the CUDA samples.
+        let peak_gflops = cores_per_mp.map(|cores| {
+            let total_cores = (mp_count * cores) as f64;
+            total_cores * (clock_rate as f64 / 1e6) * 2.0
+        });
+        if format.is_text() {
+            if let Some(gflops) = peak_gflops {
+                println!(
+                    "  Estimated Peak Single-Precision (FP32) GFLOPS: {:.2}",
+                    gflops
+                );
+            }
+        }
 
         let mem_clock = attr(dev, CU_DEVICE_ATTRIBUTE_MEMORY_CLOCK_RATE); // in kHz
-        println!(
-            "  Memory Clock rate:                             {:.0} Mhz",
-            mem_clock as f64 / 1000.0
-        );
+        if format.is_text() {
+            println!(
+                "  Memory Clock rate:                             {:.0} Mhz",
+                mem_clock as f64 / 1000.0
+            );
+        }
 
         let mem_bus_width = attr(dev, CU_DEVICE_ATTRIBUTE_GLOBAL_MEMORY_BUS_WIDTH);
-        println!(
-            "  Memory Bus Width:                              {}-bit",
-            mem_bus_width
-        );
+        if format.is_text() {
+            println!(
+                "  Memory Bus Width:                              {}-bit",
+                mem_bus_width
+            );
+        }
 
         let l2_cache = attr(dev, CU_DEVICE_ATTRIBUTE_L2_CACHE_SIZE);
-        if l2_cache > 0 {
+        if format.is_text() && l2_cache > 0 {
             println!(
                 "  L2 Cache Size:                                 {} bytes",
                 l2_cache
@@ -171,220 +579,519 @@ fn main() {
         let max_tex3d_w = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE3D_WIDTH);
         let max_tex3d_h = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE3D_HEIGHT);
         let max_tex3d_d = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE3D_DEPTH);
-        println!(
-            "  Maximum Texture Dimension Size (x,y,z)         1D=({}) 2D=({}, {}) 3D=({}, {}, {})",
-            max_tex1d, max_tex2d_w, max_tex2d_h, max_tex3d_w, max_tex3d_h, max_tex3d_d
-        );
+        if format.is_text() {
+            println!(
+                "  Maximum Texture Dimension Size (x,y,z)         1D=({}) 2D=({}, {}) 3D=({}, {}, {})",
+                max_tex1d, max_tex2d_w, max_tex2d_h, max_tex3d_w, max_tex3d_h, max_tex3d_d
+            );
+        }
 
         let max_tex1d_layered_w = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE1D_LAYERED_WIDTH);
         let max_tex1d_layered_l = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE1D_LAYERED_LAYERS);
         let max_tex2d_layered_w = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE2D_LAYERED_WIDTH);
         let max_tex2d_layered_h = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE2D_LAYERED_HEIGHT);
         let max_tex2d_layered_l = attr(dev, CU_DEVICE_ATTRIBUTE_MAXIMUM_TEXTURE2D_LAYERED_LAYERS);
-        println!(
-            "  Maximum Layered 1D Texture Size, (num) layers  1D=({}) {} layers",
-            max_tex1d_layered_w, max_tex1d_layered_l
-        );
-        println!(
-            "  Maximum Layered 2D Texture Size, (num) layers  2D=({}, {}) {} layers",
-            max_tex2d_layered_w, max_tex2d_layered_h, max_tex2d_layered_l
-        );
+        if format.is_text() {
+            println!(
+                "  Maximum Layered 1D Texture Size, (num) layers  1D=({}) {} layers",
+                max_tex1d_layered_w, max_tex1d_layered_l
+            );
+            println!(
+                "  Maximum Layered 2D Texture Size, (num) layers  2D=({}, {}) {} layers",
+                max_tex2d_layered_w, max_tex2d_layered_h, max_tex2d_layered_l
+            );
+        }
 
         let const_mem = attr(dev, CU_DEVICE_ATTRIBUTE_TOTAL_CONSTANT_MEMORY);
-        println!(
-            "  Total amount of constant memory:               {} bytes",
-            const_mem
-        );
+        if format.is_text() {
+            println!(
+                "  Total amount of constant memory:               {} bytes",
+                const_mem
+            );
+        }
 
         let shared_mem = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_SHARED_MEMORY_PER_BLOCK);
-        println!(
-            "  Total amount of shared memory per block:       {} bytes",
-            shared_mem
-        );
+        if format.is_text() {
+            println!(
+                "  Total amount of shared memory per block:       {} bytes",
+                shared_mem
+            );
+        }
 
         let shared_mem_mp = attr(
             dev,
             CU_DEVICE_ATTRIBUTE_MAX_SHARED_MEMORY_PER_MULTIPROCESSOR,
         );
-        println!(
-            "  Total shared memory per multiprocessor:        {} bytes",
-            shared_mem_mp
-        );
+        if format.is_text() {
+            println!(
+                "  Total shared memory per multiprocessor:        {} bytes",
+                shared_mem_mp
+            );
+        }
 
         let regs = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_REGISTERS_PER_BLOCK);
-        println!("  Total number of registers available per block: {}", regs);
-
         let warp_size = attr(dev, CU_DEVICE_ATTRIBUTE_WARP_SIZE);
-        println!(
-            "  Warp size:                                     {}",
-            warp_size
-        );
-
         let max_threads_mp = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_THREADS_PER_MULTIPROCESSOR);
-        println!(
-            "  Maximum number of threads per multiprocessor:  {}",
-            max_threads_mp
-        );
-
         let max_threads_block = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_THREADS_PER_BLOCK);
-        println!(
-            "  Maximum number of threads per block:           {}",
-            max_threads_block
-        );
+        if format.is_text() {
+            println!("  Total number of registers available per block: {}", regs);
+            println!(
+                "  Warp size:                                     {}",
+                warp_size
+            );
+            println!(
+                "  Maximum number of threads per multiprocessor:  {}",
+                max_threads_mp
+            );
+            println!(
+                "  Maximum number of threads per block:           {}",
+                max_threads_block
+            );
+        }
 
         let max_dim_x = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_BLOCK_DIM_X);
         let max_dim_y = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_BLOCK_DIM_Y);
         let max_dim_z = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_BLOCK_DIM_Z);
-        println!(
-            "  Max dimension size of a thread block (x,y,z):  ({}, {}, {})",
-            max_dim_x, max_dim_y, max_dim_z
-        );
+        if format.is_text() {
+            println!(
+                "  Max dimension size of a thread block (x,y,z):  ({}, {}, {})",
+                max_dim_x, max_dim_y, max_dim_z
+            );
+        }
 
         let max_grid_x = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_GRID_DIM_X);
         let max_grid_y = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_GRID_DIM_Y);
         let max_grid_z = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_GRID_DIM_Z);
-        println!(
-            "  Max dimension size of a grid size    (x,y,z):  ({}, {}, {})",
-            max_grid_x, max_grid_y, max_grid_z
-        );
+        if format.is_text() {
+            println!(
+                "  Max dimension size of a grid size    (x,y,z):  ({}, {}, {})",
+                max_grid_x, max_grid_y, max_grid_z
+            );
+        }
 
         let max_pitch = attr(dev, CU_DEVICE_ATTRIBUTE_MAX_PITCH);
-        println!(
-            "  Maximum memory pitch:                          {} bytes",
-            max_pitch
-        );
-
         let tex_align = attr(dev, CU_DEVICE_ATTRIBUTE_TEXTURE_ALIGNMENT);
-        println!(
-            "  Texture alignment:                             {} bytes",
-            tex_align
-        );
+        if format.is_text() {
+            println!(
+                "  Maximum memory pitch:                          {} bytes",
+                max_pitch
+            );
+            println!(
+                "  Texture alignment:                             {} bytes",
+                tex_align
+            );
+        }
 
         let gpu_overlap = attr(dev, CU_DEVICE_ATTRIBUTE_GPU_OVERLAP);
         let async_engines = attr(dev, CU_DEVICE_ATTRIBUTE_ASYNC_ENGINE_COUNT);
-        println!(
-            "  Concurrent copy and kernel execution:          {} with {} copy engine(s)",
-            if gpu_overlap != 0 { "Yes" } else { "No" },
-            async_engines
-        );
+        if format.is_text() {
+            println!(
+                "  Concurrent copy and kernel execution:          {} with {} copy engine(s)",
+                if gpu_overlap != 0 { "Yes" } else { "No" },
+                async_engines
+            );
+        }
 
         let kernel_timeout = attr(dev, CU_DEVICE_ATTRIBUTE_KERNEL_EXEC_TIMEOUT);
-        println!(
-            "  Run time limit on kernels:                     {}",
-            if kernel_timeout != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Run time limit on kernels:                     {}",
+                if kernel_timeout != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let integrated = attr(dev, CU_DEVICE_ATTRIBUTE_INTEGRATED);
-        println!(
-            "  Integrated GPU sharing Host Memory:            {}",
-            if integrated != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Integrated GPU sharing Host Memory:            {}",
+                if integrated != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let can_map = attr(dev, CU_DEVICE_ATTRIBUTE_CAN_MAP_HOST_MEMORY);
-        println!(
-            "  Support host page-locked memory mapping:       {}",
-            if can_map != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Support host page-locked memory mapping:       {}",
+                if can_map != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let surface_align = attr(dev, CU_DEVICE_ATTRIBUTE_SURFACE_ALIGNMENT);
-        println!(
-            "  Alignment requirement for Surfaces:            {}",
-            if surface_align != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Alignment requirement for Surfaces:            {}",
+                if surface_align != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let ecc = attr(dev, CU_DEVICE_ATTRIBUTE_ECC_ENABLED);
-        println!(
-            "  Device has ECC support:                        {}",
-            if ecc != 0 { "Enabled" } else { "Disabled" }
-        );
+        if format.is_text() {
+            println!(
+                "  Device has ECC support:                        {}",
+                if ecc != 0 { "Enabled" } else { "Disabled" }
+            );
+        }
 
         #[cfg(target_os = "windows")]
         {
-            let tcc = attr(dev, CU_DEVICE_ATTRIBUTE_TCC_DRIVER);
-            println!(
-                "  CUDA Device Driver Mode (TCC or WDDM):        {}",
-                if tcc != 0 {
-                    "TCC (Tesla Compute Cluster Driver)"
-                } else {
-                    "WDDM (Windows Display Driver Model)"
-                }
-            );
+            if format.is_text() {
+                let tcc = attr(dev, CU_DEVICE_ATTRIBUTE_TCC_DRIVER);
+                println!(
+                    "  CUDA Device Driver Mode (TCC or WDDM):        {}",
+                    if tcc != 0 {
+                        "TCC (Tesla Compute Cluster Driver)"
+                    } else {
+                        "WDDM (Windows Display Driver Model)"
+                    }
+                );
+            }
         }
 
         let unified = attr(dev, CU_DEVICE_ATTRIBUTE_UNIFIED_ADDRESSING);
-        println!(
-            "  Device supports Unified Addressing (UVA):      {}",
-            if unified != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Device supports Unified Addressing (UVA):      {}",
+                if unified != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let managed = attr(dev, CU_DEVICE_ATTRIBUTE_MANAGED_MEMORY);
-        println!(
-            "  Device supports Managed Memory:                {}",
-            if managed != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Device supports Managed Memory:                {}",
+                if managed != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let preemption = attr(dev, CU_DEVICE_ATTRIBUTE_COMPUTE_PREEMPTION_SUPPORTED);
-        println!(
-            "  Device supports Compute Preemption:            {}",
-            if preemption != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Device supports Compute Preemption:            {}",
+                if preemption != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let coop_launch = attr(dev, CU_DEVICE_ATTRIBUTE_COOPERATIVE_LAUNCH);
-        println!(
-            "  Supports Cooperative Kernel Launch:            {}",
-            if coop_launch != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Supports Cooperative Kernel Launch:            {}",
+                if coop_launch != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let coop_multi = attr(dev, CU_DEVICE_ATTRIBUTE_COOPERATIVE_MULTI_DEVICE_LAUNCH);
-        println!(
-            "  Supports MultiDevice Co-op Kernel Launch:      {}",
-            if coop_multi != 0 { "Yes" } else { "No" }
-        );
+        if format.is_text() {
+            println!(
+                "  Supports MultiDevice Co-op Kernel Launch:      {}",
+                if coop_multi != 0 { "Yes" } else { "No" }
+            );
+        }
 
         let pci_domain = attr(dev, CU_DEVICE_ATTRIBUTE_PCI_DOMAIN_ID);
         let pci_bus = attr(dev, CU_DEVICE_ATTRIBUTE_PCI_BUS_ID);
         let pci_device = attr(dev, CU_DEVICE_ATTRIBUTE_PCI_DEVICE_ID);
-        println!(
-            "  Device PCI Domain ID / Bus ID / location ID:   {} / {} / {}",
-            pci_domain, pci_bus, pci_device
-        );
+        if format.is_text() {
+            println!(
+                "  Device PCI Domain ID / Bus ID / location ID:   {} / {} / {}",
+                pci_domain, pci_bus, pci_device
+            );
+        }
 
         let compute_mode = attr(dev, CU_DEVICE_ATTRIBUTE_COMPUTE_MODE);
-        println!("  Compute Mode:",);
-        println!("     < {} >", compute_mode_str(compute_mode));
+        if format.is_text() {
+            println!("  Compute Mode:",);
+            println!("     < {} >", compute_mode_str(compute_mode));
+            println!();
+        }
+
+        reports.push(DeviceReport {
+            index: i,
+            name,
+            total_memory_bytes: total_mem,
+            compute_capability: ComputeCapability { major, minor },
+            multiprocessor_count: mp_count,
+            cuda_cores: cores_per_mp.map(|cores| mp_count * cores),
+            peak_gflops_fp32: peak_gflops,
+            clocks: Clocks {
+                gpu_max_clock_mhz: clock_rate as f64 / 1000.0,
+                memory_clock_mhz: mem_clock as f64 / 1000.0,
+            },
+            cache_sizes: CacheSizes {
+                l2_cache_bytes: l2_cache,
+                shared_mem_per_block_bytes: shared_mem,
+                shared_mem_per_multiprocessor_bytes: shared_mem_mp,
+                total_constant_memory_bytes: const_mem,
+            },
+            feature_flags: FeatureFlags {
+                concurrent_copy_and_kernel_execution: gpu_overlap != 0,
+                run_time_limit_on_kernels: kernel_timeout != 0,
+                integrated: integrated != 0,
+                can_map_host_memory: can_map != 0,
+                ecc_enabled: ecc != 0,
+                unified_addressing: unified != 0,
+                managed_memory: managed != 0,
+                compute_preemption: preemption != 0,
+                cooperative_launch: coop_launch != 0,
+                cooperative_multi_device_launch: coop_multi != 0,
+            },
+            pci: PciLocation {
+                domain: pci_domain,
+                bus: pci_bus,
+                device: pci_device,
+            },
+            compute_mode: compute_mode_str(compute_mode).to_string(),
+        });
+    }
 
-        println!();
+    if filters.is_active() {
+        if devices.is_empty() {
+            let message =
+                "No CUDA device satisfies the requested constraints (--min-cc, --min-memory, --device)";
+            if format.is_text() {
+                eprintln!("{}", message);
+            } else {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&ErrorReport { error: message }).unwrap()
+                );
+            }
+            std::process::exit(2);
+        }
+        if format.is_text() {
+            println!(
+                "{} of {} device(s) satisfy the requested constraints\n",
+                devices.len(),
+                dev_count
+            );
+        }
     }
 
-    // Peer-to-peer access matrix for multi-GPU systems
+    // Peer-to-peer bandwidth matrix for multi-GPU systems. One context per device is created
+    // up front and reused across every pair below, rather than recreated on each matrix cell.
+    let mut p2p_matrix: Vec<Vec<Option<f64>>> = Vec::new();
     if devices.len() > 1 {
-        println!("deviceQuery, Pair-to-Pair GPU Bandwidth Matrix (in GB/s)");
-        print!("   D\\D");
-        for j in 0..devices.len() {
-            print!("{:>6}", j);
-        }
-        println!();
-
-        for (i, &dev) in devices.iter().enumerate() {
-            print!("   {:>3}", i);
-            for (j, &peer) in devices.iter().enumerate() {
-                if i == j {
-                    print!("   Yes");
-                } else {
-                    let access = can_access_peer(dev, peer);
-                    print!("   {}", if access { "Yes" } else { "No" });
+        let contexts: Vec<Option<sys::CUcontext>> =
+            devices.iter().map(|&(_, dev)| create_context(dev)).collect();
+
+        for (&(_, dev), &ctx) in devices.iter().zip(contexts.iter()) {
+            let mut row = Vec::with_capacity(devices.len());
+            for (&(_, peer), &peer_ctx) in devices.iter().zip(contexts.iter()) {
+                match (ctx, peer_ctx) {
+                    (Some(ctx), Some(peer_ctx)) if dev == peer || can_access_peer(dev, peer) => {
+                        row.push(measure_bandwidth_gbps(ctx, peer_ctx, dev == peer));
+                    }
+                    _ => row.push(None),
                 }
             }
+            p2p_matrix.push(row);
+        }
+
+        for ctx in contexts.into_iter().flatten() {
+            unsafe {
+                sys::cuCtxDestroy_v2(ctx);
+            }
+        }
+    }
+
+    let best = fastest_device(&reports).map(|r| r.index);
+
+    if format.is_text() {
+        if devices.len() > 1 {
+            println!("deviceQuery, Pair-to-Pair GPU Bandwidth Matrix (in GB/s)");
+            print!("   D\\D");
+            for &(index, _) in &devices {
+                print!("{:>8}", index);
+            }
+            println!();
+
+            for (&(index, _), row) in devices.iter().zip(p2p_matrix.iter()) {
+                print!("   {:>3}", index);
+                for cell in row {
+                    match cell {
+                        Some(gbps) => print!("{:>8.1}", gbps),
+                        None => print!("{:>8}", "N/A"),
+                    }
+                }
+                println!();
+            }
             println!();
         }
-        println!();
+
+        if let Some(index) = best {
+            println!(
+                "Fastest device overall (highest estimated peak GFLOPS): Device {}\n",
+                index
+            );
+        }
+
+        println!(
+            "deviceQuery, CUDA Driver = {}.{}, CUDA Runtime = {}.{}\n",
+            driver_ver / 1000,
+            (driver_ver % 1000) / 10,
+            runtime_ver / 1000,
+            (runtime_ver % 1000) / 10
+        );
+
+        println!("Result = PASS");
+    } else {
+        let report = Report {
+            driver_version: format!("{}.{}", driver_ver / 1000, (driver_ver % 1000) / 10),
+            runtime_version: format!("{}.{}", runtime_ver / 1000, (runtime_ver % 1000) / 10),
+            device_count: reports.len(),
+            devices: reports,
+            p2p_bandwidth_matrix_gbps: p2p_matrix,
+            fastest_device_index: best,
+        };
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(values: &[&str]) -> Vec<String> {
+        values.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_format_defaults_to_text() {
+        assert_eq!(parse_format(&args(&["deviceQuery"])), OutputFormat::Text);
     }
 
-    println!(
-        "deviceQuery, CUDA Driver = {}.{}\n",
-        driver_ver / 1000,
-        (driver_ver % 1000) / 10
-    );
+    #[test]
+    fn parse_format_reads_json() {
+        assert_eq!(
+            parse_format(&args(&["deviceQuery", "--format", "json"])),
+            OutputFormat::Json
+        );
+    }
+
+    #[test]
+    fn parse_format_falls_back_on_unknown_value() {
+        assert_eq!(
+            parse_format(&args(&["deviceQuery", "--format", "xml"])),
+            OutputFormat::Text
+        );
+    }
+
+    fn make_report(
+        index: c_int,
+        total_memory_bytes: u64,
+        peak_gflops_fp32: Option<f64>,
+    ) -> DeviceReport {
+        DeviceReport {
+            index,
+            name: format!("device {}", index),
+            total_memory_bytes,
+            compute_capability: ComputeCapability { major: 0, minor: 0 },
+            multiprocessor_count: 0,
+            cuda_cores: None,
+            peak_gflops_fp32,
+            clocks: Clocks {
+                gpu_max_clock_mhz: 0.0,
+                memory_clock_mhz: 0.0,
+            },
+            cache_sizes: CacheSizes {
+                l2_cache_bytes: 0,
+                shared_mem_per_block_bytes: 0,
+                shared_mem_per_multiprocessor_bytes: 0,
+                total_constant_memory_bytes: 0,
+            },
+            feature_flags: FeatureFlags {
+                concurrent_copy_and_kernel_execution: false,
+                run_time_limit_on_kernels: false,
+                integrated: false,
+                can_map_host_memory: false,
+                ecc_enabled: false,
+                unified_addressing: false,
+                managed_memory: false,
+                compute_preemption: false,
+                cooperative_launch: false,
+                cooperative_multi_device_launch: false,
+            },
+            pci: PciLocation {
+                domain: 0,
+                bus: 0,
+                device: 0,
+            },
+            compute_mode: "Default".to_string(),
+        }
+    }
+
+    #[test]
+    fn fastest_device_picks_highest_gflops() {
+        let reports = vec![
+            make_report(0, 8_000_000_000, Some(10.0)),
+            make_report(1, 8_000_000_000, Some(20.0)),
+        ];
+        assert_eq!(fastest_device(&reports).unwrap().index, 1);
+    }
 
-    println!("Result = PASS");
+    #[test]
+    fn fastest_device_breaks_ties_by_memory() {
+        let reports = vec![
+            make_report(0, 4_000_000_000, Some(20.0)),
+            make_report(1, 8_000_000_000, Some(20.0)),
+        ];
+        assert_eq!(fastest_device(&reports).unwrap().index, 1);
+    }
+
+    #[test]
+    fn fastest_device_excludes_unknown_gflops() {
+        let reports = vec![make_report(0, 8_000_000_000, None)];
+        assert!(fastest_device(&reports).is_none());
+    }
+
+    #[test]
+    fn parse_compute_capability_reads_major_minor() {
+        assert_eq!(parse_compute_capability("7.5"), Some(75));
+    }
+
+    #[test]
+    fn parse_compute_capability_defaults_minor_to_zero() {
+        assert_eq!(parse_compute_capability("8"), Some(80));
+    }
+
+    #[test]
+    fn parse_compute_capability_rejects_garbage() {
+        assert_eq!(parse_compute_capability("x.y"), None);
+    }
+
+    #[test]
+    fn device_filters_allows_everything_by_default() {
+        let filters = DeviceFilters::default();
+        assert!(!filters.is_active());
+        assert!(filters.allows(0, 7, 0, 8192.0));
+    }
+
+    #[test]
+    fn device_filters_enforces_min_cc_boundary() {
+        let filters = DeviceFilters {
+            min_cc: Some(75),
+            ..Default::default()
+        };
+        assert!(filters.allows(0, 7, 5, 8192.0));
+        assert!(!filters.allows(0, 7, 0, 8192.0));
+    }
+
+    #[test]
+    fn device_filters_enforces_min_memory() {
+        let filters = DeviceFilters {
+            min_memory_mb: Some(16384),
+            ..Default::default()
+        };
+        assert!(!filters.allows(0, 9, 0, 8192.0));
+        assert!(filters.allows(0, 9, 0, 16384.0));
+    }
+
+    #[test]
+    fn device_filters_restricts_to_one_device() {
+        let filters = DeviceFilters {
+            only_device: Some(1),
+            ..Default::default()
+        };
+        assert!(!filters.allows(0, 9, 0, 8192.0));
+        assert!(filters.allows(1, 9, 0, 8192.0));
+    }
 }